@@ -3,6 +3,7 @@ use pool::Reset;
 use std::io::{self,Write};
 use nom::HexDisplay;
 use std::cmp::max;
+use libc::{self,c_void};
 
 #[derive(Debug,PartialEq,Clone)]
 pub enum InputElement {
@@ -243,6 +244,51 @@ impl BufferQueue {
     &self.buffer.data()[start..start+largest_size]
   }
 
+  /// gathers the consecutive output elements, up to the first `Splice`, into
+  /// a list of `IoSlice`s suitable for `Write::write_vectored`, so buffered
+  /// body bytes and inserted headers (like injected HTTP headers) can be
+  /// flushed to the socket in a single syscall instead of one write per
+  /// element. Kernel-spliced data cannot take part in a userspace writev, so
+  /// the gathering stops as soon as a `Splice` element is reached.
+  pub fn output_iovecs(&self) -> Vec<io::IoSlice> {
+    let mut iovecs = Vec::with_capacity(self.output_queue.len());
+    let mut cursor = 0usize;
+
+    for el in self.output_queue.iter() {
+      match el {
+        &OutputElement::Delete(sz) => cursor += sz,
+        &OutputElement::Slice(sz)  => {
+          iovecs.push(io::IoSlice::new(&self.buffer.data()[cursor..cursor+sz]));
+          cursor += sz;
+        },
+        &OutputElement::Insert(ref v) => iovecs.push(io::IoSlice::new(&v[..])),
+        &OutputElement::Splice(_)     => break,
+      }
+    }
+
+    iovecs
+  }
+
+  /// returns true if the output queue can be forwarded through `splice(2)`
+  /// without falling back to the copy path: only `Splice` elements are
+  /// pending, with no `Insert`/`Delete` to force userspace handling and no
+  /// `Slice` whose bytes still sit in the userspace buffer
+  pub fn output_ready_for_splice(&self) -> bool {
+    self.output_queue.iter().all(|el| match el {
+      &OutputElement::Splice(_) => true,
+      &OutputElement::Slice(_) | &OutputElement::Insert(_) | &OutputElement::Delete(_) => false,
+    })
+  }
+
+  /// if the next element to consume is a `Splice`, returns the number of
+  /// bytes it still has to move from the kernel to the socket
+  pub fn next_splice_size(&self) -> Option<usize> {
+    match self.output_queue.first() {
+      Some(&OutputElement::Splice(sz)) => Some(sz),
+      _ => None,
+    }
+  }
+
   /// should only be called with a count inferior to self.input_data_size()
   pub fn consume_output_data(&mut self, size: usize) {
     let mut to_consume = size;
@@ -329,6 +375,183 @@ impl Reset for BufferQueue {
   }
 }
 
+/// the two ends of the pipe used as the kernel-side relay for `splice(2)`:
+/// the source is spliced into the write end, then the read end is spliced out
+/// to the destination socket
+#[derive(Debug)]
+pub struct SplicePipe {
+  pub read_end:  i32,
+  pub write_end: i32,
+}
+
+impl SplicePipe {
+  pub fn new() -> io::Result<SplicePipe> {
+    let mut fds = [0i32; 2];
+    let res = unsafe { libc::pipe(fds.as_mut_ptr()) };
+    if res < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(SplicePipe { read_end: fds[0], write_end: fds[1] })
+  }
+}
+
+impl Drop for SplicePipe {
+  fn drop(&mut self) {
+    unsafe {
+      libc::close(self.read_end);
+      libc::close(self.write_end);
+    }
+  }
+}
+
+fn is_would_block(err: &io::Error) -> bool {
+  err.kind() == io::ErrorKind::WouldBlock
+}
+
+/// drives zero-copy passthrough forwarding between two non-blocking file
+/// descriptors, reusing one relay pipe and one scratch buffer across calls
+/// instead of allocating them per iteration. `source` and `destination` are
+/// expected to be set `O_NONBLOCK` by the caller, as is usual in an
+/// event-loop driven proxy.
+pub struct PassthroughForwarder {
+  pipe:          SplicePipe,
+  /// bytes already spliced into `pipe` but not yet spliced out to the
+  /// destination (a partial out-splice, or a destination not ready yet)
+  pipe_residual: usize,
+  /// reused across calls to `copy_forward` so the copy-path fallback does
+  /// not allocate on every invocation
+  scratch:       Vec<u8>,
+}
+
+impl PassthroughForwarder {
+  pub fn new(max_size: usize) -> io::Result<PassthroughForwarder> {
+    Ok(PassthroughForwarder {
+      pipe:          SplicePipe::new()?,
+      pipe_residual: 0,
+      scratch:       vec![0u8; max_size],
+    })
+  }
+
+  /// moves bytes from `source` to `destination`, through the relay pipe
+  /// when possible, recording the move in `buffer` so the accounting stays
+  /// consistent with the copy path. Falls back to `copy_forward` when the
+  /// output queue has a pending `Insert`/`Delete` that needs userspace
+  /// handling, or when splicing in fails outright (e.g. `ENOSYS` on a
+  /// kernel/fs combination that doesn't support it). A `WouldBlock` from
+  /// either splice call is not an error: it means the destination isn't
+  /// ready yet, or there is nothing left to read right now, so any bytes
+  /// already sitting in the relay pipe are left there (`pipe_residual`)
+  /// for the next call to drain before pulling in more.
+  pub fn forward(&mut self, buffer: &mut BufferQueue, source: i32, destination: i32) -> io::Result<usize> {
+    if !buffer.output_ready_for_splice() {
+      return self.copy_forward(buffer, source, destination);
+    }
+
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+
+    if self.pipe_residual == 0 {
+      let moved_in = unsafe {
+        libc::splice(source, ::std::ptr::null_mut(), self.pipe.write_end, ::std::ptr::null_mut(),
+          self.scratch.len(), flags)
+      };
+      if moved_in < 0 {
+        let err = io::Error::last_os_error();
+        if is_would_block(&err) {
+          return Ok(0);
+        }
+        return self.copy_forward(buffer, source, destination);
+      }
+      if moved_in == 0 {
+        return Ok(0);
+      }
+      // a passthrough connection is never parsed, so the spliced bytes are
+      // accounted for and immediately handed off instead of waiting in
+      // unparsed_data() for a parser that will never run
+      buffer.spliced_input(moved_in as usize);
+      buffer.consume_parsed_data(moved_in as usize);
+      self.pipe_residual = moved_in as usize;
+    }
+
+    let moved_out = unsafe {
+      libc::splice(self.pipe.read_end, ::std::ptr::null_mut(), destination, ::std::ptr::null_mut(),
+        self.pipe_residual, flags)
+    };
+    if moved_out < 0 {
+      let err = io::Error::last_os_error();
+      if is_would_block(&err) {
+        // destination isn't ready: the residual bytes stay queued in the
+        // pipe for the next call, nothing was lost or double-counted
+        return Ok(0);
+      }
+      return Err(err);
+    }
+
+    buffer.splice_output(moved_out as usize);
+    buffer.consume_output_data(moved_out as usize);
+    self.pipe_residual -= moved_out as usize;
+
+    Ok(moved_out as usize)
+  }
+
+  /// userspace fallback for `forward`: reads into `buffer` using the
+  /// reusable scratch buffer, then flushes whatever is ready to go out
+  /// (slices, inserted bytes, deletes) with `output_iovecs` so inserted
+  /// headers still go out in one syscall
+  fn copy_forward(&mut self, buffer: &mut BufferQueue, source: i32, destination: i32) -> io::Result<usize> {
+    let read = unsafe {
+      libc::read(source, self.scratch.as_mut_ptr() as *mut c_void, self.scratch.len())
+    };
+    if read < 0 {
+      let err = io::Error::last_os_error();
+      if is_would_block(&err) {
+        return Ok(0);
+      }
+      return Err(err);
+    }
+    if read > 0 {
+      let buffered = buffer.write(&self.scratch[..read as usize])?;
+      buffer.slice_output(buffered);
+    }
+
+    let iovecs = buffer.output_iovecs();
+    if iovecs.is_empty() {
+      return Ok(0);
+    }
+
+    let written = unsafe {
+      libc::writev(destination, iovecs.as_ptr() as *const libc::iovec, iovecs.len() as i32)
+    };
+    if written < 0 {
+      let err = io::Error::last_os_error();
+      if is_would_block(&err) {
+        return Ok(0);
+      }
+      return Err(err);
+    }
+    buffer.consume_output_data(written as usize);
+
+    Ok(written as usize)
+  }
+
+  /// drives `forward` until it makes no more progress in this pass
+  /// (`WouldBlock` on either end, or the source has nothing left to give
+  /// right now), mirroring the read-buffer-parse-write structure of the
+  /// parsed path: read into the queue, let the classifier decide
+  /// slice-vs-splice, write out, repeat. Meant to be called once per
+  /// readiness notification from the event loop.
+  pub fn forward_all(&mut self, buffer: &mut BufferQueue, source: i32, destination: i32) -> io::Result<usize> {
+    let mut total = 0;
+    loop {
+      let moved = self.forward(buffer, source, destination)?;
+      if moved == 0 {
+        break;
+      }
+      total += moved;
+    }
+    Ok(total)
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -419,4 +642,197 @@ mod tests {
 
     b.write(&b"KLMNOP"[..]);
   }
+
+  #[test]
+  fn output_iovecs() {
+    let mut b = BufferQueue {
+      parsed_position:        0,
+      output_position:        0,
+      buffer_position:        0,
+      start_parsing_position: 0,
+      buffer:                 Buffer::from_slice(b"ABCDEFGHIJ"),
+      input_queue:            vec!(),
+      output_queue:           vec!(
+        OutputElement::Delete(2),
+        OutputElement::Slice(3),
+        OutputElement::Insert(Vec::from(&b"test"[..])),
+        OutputElement::Slice(5),
+      ),
+    };
+
+    let iovecs = b.output_iovecs();
+    let gathered: Vec<u8> = iovecs.iter().flat_map(|s| s.to_vec()).collect();
+    assert_eq!(&gathered[..], &b"CDEtestFGHIJ"[..]);
+    assert!(!b.output_ready_for_splice());
+
+    let mut spliced = BufferQueue {
+      parsed_position:        0,
+      output_position:        0,
+      buffer_position:        0,
+      start_parsing_position: 0,
+      buffer:                 Buffer::from_slice(b"ABCDEFGHIJ"),
+      input_queue:            vec!(),
+      output_queue:           vec!(
+        OutputElement::Slice(3),
+        OutputElement::Splice(4),
+      ),
+    };
+
+    let iovecs = spliced.output_iovecs();
+    let gathered: Vec<u8> = iovecs.iter().flat_map(|s| s.to_vec()).collect();
+    assert_eq!(&gathered[..], &b"ABC"[..]);
+    assert_eq!(spliced.next_splice_size(), None);
+
+    spliced.consume_output_data(3);
+    assert_eq!(spliced.next_splice_size(), Some(4));
+    assert!(spliced.output_ready_for_splice());
+  }
+
+  fn pipe_pair() -> (i32, i32) {
+    let mut fds = [0i32; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    (fds[0], fds[1])
+  }
+
+  fn set_nonblocking(fd: i32) {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL, 0) };
+    assert!(flags >= 0);
+    assert_eq!(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) }, 0);
+  }
+
+  #[test]
+  fn forward_moves_bytes_kernel_to_kernel() {
+    let (source_read, source_write)           = pipe_pair();
+    let (destination_read, destination_write) = pipe_pair();
+    let mut forwarder = PassthroughForwarder::new(4096).expect("could not create forwarder");
+
+    let payload = b"hello from the kernel";
+    assert_eq!(
+      unsafe { libc::write(source_write, payload.as_ptr() as *const c_void, payload.len()) },
+      payload.len() as isize
+    );
+
+    let mut buffer = BufferQueue::with_capacity(128);
+    let moved = forwarder.forward(&mut buffer, source_read, destination_write)
+      .expect("forward failed");
+    assert_eq!(moved, payload.len());
+    assert!(buffer.output_queue.is_empty());
+    assert_eq!(forwarder.pipe_residual, 0);
+
+    let mut received = vec![0u8; payload.len()];
+    assert_eq!(
+      unsafe { libc::read(destination_read, received.as_mut_ptr() as *mut c_void, received.len()) },
+      payload.len() as isize
+    );
+    assert_eq!(&received[..], &payload[..]);
+
+    unsafe {
+      libc::close(source_read);
+      libc::close(source_write);
+      libc::close(destination_read);
+      libc::close(destination_write);
+    }
+  }
+
+  #[test]
+  fn forward_falls_back_to_copy_with_pending_insert() {
+    let (source_read, source_write)           = pipe_pair();
+    let (destination_read, destination_write) = pipe_pair();
+    let mut forwarder = PassthroughForwarder::new(4096).expect("could not create forwarder");
+
+    let payload = b"body";
+    assert_eq!(
+      unsafe { libc::write(source_write, payload.as_ptr() as *const c_void, payload.len()) },
+      payload.len() as isize
+    );
+
+    let mut buffer = BufferQueue::with_capacity(128);
+    // an inserted header forces the copy path: splicing can't interleave
+    // userspace-provided bytes with kernel-spliced ones
+    buffer.insert_output(Vec::from(&b"X-Header: 1\r\n"[..]));
+    assert!(!buffer.output_ready_for_splice());
+
+    let moved = forwarder.forward(&mut buffer, source_read, destination_write)
+      .expect("forward failed");
+    assert_eq!(moved, b"X-Header: 1\r\nbody".len());
+    assert!(buffer.output_queue.is_empty());
+
+    let mut received = vec![0u8; moved];
+    assert_eq!(
+      unsafe { libc::read(destination_read, received.as_mut_ptr() as *mut c_void, received.len()) },
+      moved as isize
+    );
+    assert_eq!(&received[..], &b"X-Header: 1\r\nbody"[..]);
+
+    unsafe {
+      libc::close(source_read);
+      libc::close(source_write);
+      libc::close(destination_read);
+      libc::close(destination_write);
+    }
+  }
+
+  #[test]
+  fn forward_keeps_residual_in_pipe_across_eagain_and_drains_it_later() {
+    let (source_read, source_write)           = pipe_pair();
+    let (destination_read, destination_write) = pipe_pair();
+    set_nonblocking(destination_write);
+
+    let capacity = unsafe { libc::fcntl(destination_write, libc::F_GETPIPE_SZ) };
+    assert!(capacity > 0);
+    let capacity = capacity as usize;
+
+    // fill the destination pipe completely so the next out-splice gets
+    // EAGAIN instead of completing
+    let filler = vec![0u8; capacity];
+    assert_eq!(
+      unsafe { libc::write(destination_write, filler.as_ptr() as *const c_void, filler.len()) },
+      capacity as isize
+    );
+
+    let payload = b"abcde";
+    assert_eq!(
+      unsafe { libc::write(source_write, payload.as_ptr() as *const c_void, payload.len()) },
+      payload.len() as isize
+    );
+
+    let mut forwarder = PassthroughForwarder::new(4096).expect("could not create forwarder");
+    let mut buffer = BufferQueue::with_capacity(128);
+
+    // the in-splice succeeds (relay pipe has room) but the out-splice
+    // can't, since the destination is full: no bytes should be lost, and
+    // none should be double-counted once the destination drains
+    let moved = forwarder.forward(&mut buffer, source_read, destination_write)
+      .expect("forward should not error on EAGAIN");
+    assert_eq!(moved, 0);
+    assert_eq!(forwarder.pipe_residual, payload.len());
+    assert!(buffer.output_queue.is_empty());
+
+    // drain the filler so the destination has room for the residual bytes
+    let mut drained = vec![0u8; capacity];
+    assert_eq!(
+      unsafe { libc::read(destination_read, drained.as_mut_ptr() as *mut c_void, drained.len()) },
+      capacity as isize
+    );
+
+    let moved = forwarder.forward(&mut buffer, source_read, destination_write)
+      .expect("forward failed");
+    assert_eq!(moved, payload.len());
+    assert_eq!(forwarder.pipe_residual, 0);
+    assert!(buffer.output_queue.is_empty());
+
+    let mut received = vec![0u8; payload.len()];
+    assert_eq!(
+      unsafe { libc::read(destination_read, received.as_mut_ptr() as *mut c_void, received.len()) },
+      payload.len() as isize
+    );
+    assert_eq!(&received[..], &payload[..]);
+
+    unsafe {
+      libc::close(source_read);
+      libc::close(source_write);
+      libc::close(destination_read);
+      libc::close(destination_write);
+    }
+  }
 }